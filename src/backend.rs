@@ -0,0 +1,1015 @@
+//! Storage backends for the aggregator.
+//!
+//! `Aggregator` drives the step1/step2 and candle pipelines purely in terms of the `Backend`
+//! trait, so the same logic runs unchanged against MySQL or Postgres. Each backend owns its own
+//! connection pool and speaks whatever dialect its engine needs (table existence checks, table
+//! renames, and the bulk-copy path in particular differ a lot between the two).
+
+use chrono::Utc;
+use log::trace;
+use mysql::{Opts, Pool as MysqlPool, PooledConn};
+use r2d2::Pool as R2d2Pool;
+use r2d2_postgres::{postgres::NoTls, PostgresConnectionManager};
+
+use crate::config::PoolConfig;
+use crate::metrics::RunStats;
+
+/// DB operations an `Aggregator` needs, factored out so it isn't locked to one engine.
+///
+/// Methods with a default implementation are portable across engines as long as the
+/// implementor provides `execute`; backends override them when a faster, engine-specific
+/// path exists (MySQL's `OUTFILE`/`LOAD DATA`, Postgres's `COPY`).
+pub trait Backend: Send + Sync {
+    /// Runs a statement that doesn't return rows, and reports how many rows it touched.
+    fn execute(&self, stmt: &str) -> u64;
+
+    /// Whether a table with this name currently exists.
+    fn check_existence(&self, table_name: &str) -> bool;
+
+    /// Renames a table. An existing `to` is not dropped first, so the rename fails if `to`
+    /// already exists.
+    fn rename_table(&self, orig: &str, to: &str);
+
+    fn create_timestamp_table(&self, table_name: &str);
+    fn create_aggregate_table(&self, table_name: &str);
+    fn create_candle_table(&self, table_name: &str);
+
+    fn drop_table(&self, table_name: &str) {
+        self.execute(&format!("DROP TABLE IF EXISTS {};", table_name));
+    }
+
+    /// Copies distinct `traded_at` values not yet present in `dest_table` from `source_table`,
+    /// up to `limit` rows. Backends should override this with their fastest bulk-load
+    /// mechanism; the default is the portable fallback below.
+    fn bulk_insert_distinct_traded_at(
+        &self,
+        source_table: &str,
+        dest_table: &str,
+        limit: u32,
+    ) -> u64 {
+        self.bulk_insert_distinct_traded_at_streaming(source_table, dest_table, limit)
+    }
+
+    /// Portable `INSERT ... SELECT` bulk-copy path, usable by any backend regardless of
+    /// whether it has access to a fast file-based bulk-load mechanism.
+    ///
+    /// Reads `dest_table`'s watermark with a separate query first rather than nesting it as a
+    /// `FROM` subquery of the `INSERT INTO {dest} SELECT ...` statement: MySQL rejects selecting
+    /// from the very table being inserted into (error 1093, "can't specify target table for
+    /// update in FROM clause").
+    fn bulk_insert_distinct_traded_at_streaming(
+        &self,
+        source_table: &str,
+        dest_table: &str,
+        limit: u32,
+    ) -> u64 {
+        let watermark = self
+            .max_traded_at(dest_table)
+            .unwrap_or_else(|| "2000-01-01 00:00:00.000".to_string());
+
+        let stmt = format!(
+            r#"INSERT INTO {dest}
+                SELECT DISTINCT traded_at
+                FROM {source}
+                WHERE traded_at > '{watermark}'
+                ORDER BY traded_at
+                LIMIT {limit}
+                ;"#,
+            dest = dest_table,
+            source = source_table,
+            limit = limit,
+            watermark = watermark
+        );
+        trace!("run stmt: {}", stmt);
+        self.execute(&stmt)
+    }
+
+    /// Latest `traded_at` already present in `table_name`, or `None` if it's empty.
+    fn max_traded_at(&self, table_name: &str) -> Option<String>;
+
+    /// Copies distinct `traded_at` values strictly greater than `since` from `source_table`
+    /// into `dest_table`, up to `limit` rows. Unlike `bulk_insert_distinct_traded_at`, the
+    /// watermark is supplied by the caller rather than read from `dest_table`'s own state, so
+    /// it stays correct even when `dest_table` is drained between calls.
+    fn copy_distinct_traded_at_since(
+        &self,
+        source_table: &str,
+        dest_table: &str,
+        since: &str,
+        limit: u32,
+    ) -> u64 {
+        let stmt = format!(
+            r#"INSERT INTO {dest}
+                SELECT DISTINCT traded_at
+                FROM {source}
+                WHERE traded_at > '{since}'
+                ORDER BY traded_at
+                LIMIT {limit}
+                ;"#,
+            dest = dest_table,
+            source = source_table,
+            since = since,
+            limit = limit
+        );
+        trace!("run stmt: {}", stmt);
+        self.execute(&stmt)
+    }
+
+    /// Aggregates one batch of `s1_table`'s timestamps into `SUM(amount)`/`AVG(price)` rows in
+    /// `s2_table`, draining the processed timestamps from `s1_table`. Returns rows written.
+    fn insert_aggregated(
+        &self,
+        s1_table: &str,
+        s2_table: &str,
+        data_table: &str,
+        limit: u32,
+    ) -> u64;
+
+    /// Buckets one batch of `data_table`'s trades into OHLCV candles and upserts them into
+    /// `candle_table`, only considering buckets newer than the latest one already stored.
+    /// Returns rows written.
+    fn move_aggregated_candles(
+        &self,
+        data_table: &str,
+        candle_table: &str,
+        interval_secs: u32,
+        limit: u32,
+    ) -> u64;
+
+    /// Re-aggregates exactly the latest bucket already stored in `candle_table` (a no-op if
+    /// it's empty), in case more trades landed in it since the previous run. Unlike
+    /// `move_aggregated_candles`, this never expands to new buckets, so it's safe to call once
+    /// per run rather than looping to a fixpoint.
+    fn refresh_latest_candle(&self, data_table: &str, candle_table: &str, interval_secs: u32);
+
+    /// Copies up to `limit` new distinct `traded_at` values from `source_table` into
+    /// `tmp_table`, starting from (and including) the latest `traded_at` already aggregated
+    /// into `target_table` rather than strictly after it, since more trades may have landed at
+    /// that exact timestamp since the previous run. Returns rows written.
+    fn collect_incremental_traded_at(
+        &self,
+        source_table: &str,
+        tmp_table: &str,
+        target_table: &str,
+        limit: u32,
+    ) -> u64;
+
+    /// Aggregates one batch of `tmp_table`'s timestamps into `SUM(amount)`/`AVG(price)` rows,
+    /// upserted directly into the live `target_table` keyed on `traded_at`, draining the
+    /// processed timestamps from `tmp_table`. Returns rows written.
+    fn upsert_aggregated(
+        &self,
+        target_table: &str,
+        tmp_table: &str,
+        data_table: &str,
+        limit: u32,
+    ) -> u64;
+
+    /// Total row count of a table, used for the optional reporting pass.
+    fn count_rows(&self, table_name: &str) -> u64;
+
+    /// Creates the `aggregation_stats` table if it doesn't already exist.
+    fn ensure_stats_table(&self);
+
+    /// Records one run's stats into `aggregation_stats`.
+    fn insert_run_stats(&self, stats: &RunStats);
+}
+
+// `interval_secs`-wide bucket start for `alias.traded_at`, e.g. "t" when correlating against a
+// row alias rather than the data table itself.
+fn mysql_bucket_expr(alias: &str, interval_secs: u32) -> String {
+    format!(
+        "FROM_UNIXTIME(FLOOR(UNIX_TIMESTAMP({}.traded_at)/{n})*{n})",
+        alias,
+        n = interval_secs
+    )
+}
+
+/// MySQL-backed storage, using `OUTFILE`/`LOAD DATA` for the bulk-copy path by default.
+#[derive(Debug)]
+pub struct MysqlBackend {
+    pool: MysqlPool,
+    /// Whether the connecting user has the `FILE` privilege required for `OUTFILE`/`LOAD DATA`.
+    /// When `false`, the portable `INSERT ... SELECT` fallback is used instead.
+    file_privilege: bool,
+    acquire_timeout_ms: u32,
+}
+
+impl MysqlBackend {
+    pub fn new(url: &str, config: PoolConfig) -> Self {
+        Self::with_file_privilege(url, config, true)
+    }
+
+    /// Creates a backend whose bulk-copy path uses `OUTFILE`/`LOAD DATA` only when
+    /// `file_privilege` is `true`; with `false` it never relies on the `FILE` privilege, for
+    /// deployments whose MySQL user cannot be granted that access (e.g. no `secure_file_priv`).
+    pub fn with_file_privilege(url: &str, config: PoolConfig, file_privilege: bool) -> Self {
+        let opts = Opts::from_url(url).expect("invalid MySQL connection url");
+        let pool = MysqlPool::new_manual(config.min_connections, config.max_connections, opts)
+            .expect("cannot connect to MySQL instance");
+        Self {
+            pool,
+            file_privilege,
+            acquire_timeout_ms: config.acquire_timeout.as_millis() as u32,
+        }
+    }
+
+    // Acquires a connection, failing fast with a clear error instead of hanging indefinitely
+    // when the pool is exhausted.
+    fn get_conn(&self) -> PooledConn {
+        self.pool.try_get_conn(self.acquire_timeout_ms).unwrap_or_else(|e| {
+            panic!(
+                "could not acquire a MySQL connection within {}ms: {}",
+                self.acquire_timeout_ms, e
+            )
+        })
+    }
+}
+
+impl Backend for MysqlBackend {
+    fn execute(&self, stmt: &str) -> u64 {
+        self.get_conn()
+            .prep_exec(stmt, ())
+            .map(|result| result.affected_rows())
+            .unwrap()
+    }
+
+    fn check_existence(&self, table_name: &str) -> bool {
+        let existence_stmt = format!(r"SHOW TABLES LIKE '{}';", table_name);
+        let exists = self
+            .get_conn()
+            .prep_exec(existence_stmt, ())
+            .map(|result| result.map(|x| x.unwrap()).count());
+        exists.map(|e| e != 0).unwrap_or(false)
+    }
+
+    fn max_traded_at(&self, table_name: &str) -> Option<String> {
+        // CAST ... AS CHAR so this comes back as Value::Bytes: the mysql crate's FromValue for
+        // String doesn't accept the Value::Date a plain TIMESTAMP column would otherwise return.
+        self.get_conn()
+            .first_exec(
+                format!("SELECT CAST(MAX(traded_at) AS CHAR) FROM {};", table_name),
+                (),
+            )
+            .unwrap()
+            .flatten()
+    }
+
+    fn rename_table(&self, orig: &str, to: &str) {
+        self.execute(&format!(r"RENAME TABLE {} TO {};", orig, to));
+    }
+
+    fn create_timestamp_table(&self, table_name: &str) {
+        self.execute(&format!(
+            r"CREATE TABLE {} (
+                         traded_at TIMESTAMP(3) NOT NULL PRIMARY KEY
+                     );",
+            table_name
+        ));
+    }
+
+    fn create_aggregate_table(&self, table_name: &str) {
+        self.execute(&format!(
+            r"CREATE TABLE {} (
+                         traded_at TIMESTAMP(3) NOT NULL PRIMARY KEY,
+                         amount DOUBLE NOT NULL,
+                         price FLOAT NOT NULL
+                     );",
+            table_name
+        ));
+    }
+
+    fn create_candle_table(&self, table_name: &str) {
+        self.execute(&format!(
+            r"CREATE TABLE {} (
+                         bucket_start TIMESTAMP NOT NULL PRIMARY KEY,
+                         open FLOAT NOT NULL,
+                         high FLOAT NOT NULL,
+                         low FLOAT NOT NULL,
+                         close FLOAT NOT NULL,
+                         volume DOUBLE NOT NULL,
+                         trade_count INT NOT NULL
+                     );",
+            table_name
+        ));
+    }
+
+    // faster version, using OUTFILE/LOAD.
+    // https://github.com/docker-library/mysql/issues/447
+    fn bulk_insert_distinct_traded_at(
+        &self,
+        source_table: &str,
+        dest_table: &str,
+        limit: u32,
+    ) -> u64 {
+        if !self.file_privilege {
+            return self.bulk_insert_distinct_traded_at_streaming(source_table, dest_table, limit);
+        }
+
+        let out_file_name = format!("/tmp/table_{}_{}.txt", dest_table, Utc::now().timestamp_millis());
+
+        // insert
+        {
+            // cannot use prepared statement
+            let stmt = format!(
+                r#"SELECT DISTINCT traded_at
+                    FROM {}
+                    WHERE traded_at >
+                    COALESCE(
+                        (SELECT traded_at FROM {} ORDER BY traded_at DESC LIMIT 1),
+                        '2000-01-01 00:00:00.000'
+                    )
+                    ORDER BY traded_at
+                    LIMIT {lm}
+                    INTO OUTFILE '{of}'
+                        ;"#,
+                source_table,
+                dest_table,
+                of = out_file_name,
+                lm = limit
+            );
+            trace!("run stmt: {}", stmt);
+            self.get_conn().query(stmt).unwrap();
+        }
+        // load
+        {
+            // cannot use prepared statement
+            let stmt = format!(
+                r#"LOAD DATA INFILE '{of}' INTO TABLE {};"#,
+                dest_table,
+                of = out_file_name
+            );
+            trace!("run stmt: {}", stmt);
+            self.get_conn()
+                .query(stmt)
+                .map(|result| result.affected_rows())
+                .unwrap()
+        }
+    }
+
+    fn insert_aggregated(
+        &self,
+        s1_table: &str,
+        s2_table: &str,
+        data_table: &str,
+        limit: u32,
+    ) -> u64 {
+        self.get_conn()
+            .start_transaction(false, None, None)
+            .and_then(|mut tx| {
+                let stmt = format!(
+                    r#"INSERT INTO {s2} (traded_at,amount,price)
+                        SELECT {s1}.traded_at,Sum({orig}.amount),Avg({orig}.price)
+                        FROM   {s1}
+                        LEFT JOIN {orig} ON {s1}.traded_at = {orig}.traded_at
+                        GROUP  BY traded_at
+                        LIMIT  ?
+                        ;"#,
+                    s1 = s1_table,
+                    s2 = s2_table,
+                    orig = data_table
+                );
+                trace!("agg_stmt: {}", stmt);
+                let t = tx
+                    .prep_exec(stmt, (limit,))
+                    .map(|result| result.affected_rows())
+                    .unwrap();
+
+                // drop used traded_at
+                let drop_stmt = format!(r#"DELETE FROM {s1} WHERE 1=1 LIMIT ?;"#, s1 = s1_table);
+                tx.prep_exec(drop_stmt, (limit,)).unwrap();
+
+                tx.commit().unwrap();
+
+                Ok(t)
+            })
+            .unwrap()
+    }
+
+    fn move_aggregated_candles(
+        &self,
+        data_table: &str,
+        candle_table: &str,
+        interval_secs: u32,
+        limit: u32,
+    ) -> u64 {
+        let bucket = mysql_bucket_expr(data_table, interval_secs);
+        let bucket_t = mysql_bucket_expr("t", interval_secs);
+
+        let stmt = format!(
+            r#"INSERT INTO {candles} (bucket_start, open, high, low, close, volume, trade_count)
+                SELECT b.bucket_start, open_t.price, b.high, b.low, close_t.price, b.volume, b.trade_count
+                FROM (
+                    SELECT {bucket} AS bucket_start,
+                           MAX(price) AS high,
+                           MIN(price) AS low,
+                           SUM(amount) AS volume,
+                           COUNT(*) AS trade_count
+                    FROM {orig}
+                    WHERE {bucket} >
+                        COALESCE(
+                            (SELECT MAX(bucket_start) FROM {candles}),
+                            '2000-01-01 00:00:00'
+                        )
+                    GROUP BY bucket_start
+                    ORDER BY bucket_start
+                    LIMIT ?
+                ) b
+                JOIN {orig} open_t ON open_t.id = (
+                    SELECT t.id FROM {orig} t
+                    WHERE {bucket_t} = b.bucket_start
+                    ORDER BY t.traded_at ASC, t.id ASC
+                    LIMIT 1
+                )
+                JOIN {orig} close_t ON close_t.id = (
+                    SELECT t.id FROM {orig} t
+                    WHERE {bucket_t} = b.bucket_start
+                    ORDER BY t.traded_at DESC, t.id DESC
+                    LIMIT 1
+                )
+                ON DUPLICATE KEY UPDATE
+                    open = VALUES(open),
+                    high = VALUES(high),
+                    low = VALUES(low),
+                    close = VALUES(close),
+                    volume = VALUES(volume),
+                    trade_count = VALUES(trade_count)
+                ;"#,
+            candles = candle_table,
+            orig = data_table,
+            bucket = bucket,
+            bucket_t = bucket_t,
+        );
+        trace!("candle_stmt: {}", stmt);
+        self.get_conn()
+            .prep_exec(stmt, (limit,))
+            .map(|result| result.affected_rows())
+            .unwrap()
+    }
+
+    // Several trades can share the exact same traded_at, so open/close are picked by ordering on
+    // (traded_at, id) rather than joining back on traded_at alone (which can match more than one
+    // row and fan out the bucket) or on MIN(id)/MAX(id) alone (wrong whenever insertion id isn't
+    // monotonic with traded_at, e.g. a backfill). id only breaks ties within the same timestamp.
+    fn refresh_latest_candle(&self, data_table: &str, candle_table: &str, interval_secs: u32) {
+        let bucket = mysql_bucket_expr(data_table, interval_secs);
+        let bucket_t = mysql_bucket_expr("t", interval_secs);
+
+        let stmt = format!(
+            r#"INSERT INTO {candles} (bucket_start, open, high, low, close, volume, trade_count)
+                SELECT b.bucket_start, open_t.price, b.high, b.low, close_t.price, b.volume, b.trade_count
+                FROM (
+                    SELECT {bucket} AS bucket_start,
+                           MAX(price) AS high,
+                           MIN(price) AS low,
+                           SUM(amount) AS volume,
+                           COUNT(*) AS trade_count
+                    FROM {orig}
+                    WHERE {bucket} = (SELECT MAX(bucket_start) FROM {candles})
+                    GROUP BY bucket_start
+                ) b
+                JOIN {orig} open_t ON open_t.id = (
+                    SELECT t.id FROM {orig} t
+                    WHERE {bucket_t} = b.bucket_start
+                    ORDER BY t.traded_at ASC, t.id ASC
+                    LIMIT 1
+                )
+                JOIN {orig} close_t ON close_t.id = (
+                    SELECT t.id FROM {orig} t
+                    WHERE {bucket_t} = b.bucket_start
+                    ORDER BY t.traded_at DESC, t.id DESC
+                    LIMIT 1
+                )
+                ON DUPLICATE KEY UPDATE
+                    open = VALUES(open),
+                    high = VALUES(high),
+                    low = VALUES(low),
+                    close = VALUES(close),
+                    volume = VALUES(volume),
+                    trade_count = VALUES(trade_count)
+                ;"#,
+            candles = candle_table,
+            orig = data_table,
+            bucket = bucket,
+            bucket_t = bucket_t,
+        );
+        trace!("refresh_candle_stmt: {}", stmt);
+        self.get_conn().prep_exec(stmt, ()).unwrap();
+    }
+
+    fn collect_incremental_traded_at(
+        &self,
+        source_table: &str,
+        tmp_table: &str,
+        target_table: &str,
+        limit: u32,
+    ) -> u64 {
+        let stmt = format!(
+            r#"INSERT INTO {tmp}
+                SELECT DISTINCT traded_at
+                FROM {source}
+                WHERE traded_at >=
+                    COALESCE(
+                        (SELECT MAX(traded_at) FROM {target}),
+                        '2000-01-01 00:00:00.000'
+                    )
+                  AND traded_at >
+                    COALESCE(
+                        (SELECT MAX(traded_at) FROM {tmp}),
+                        '1970-01-01 00:00:00.000'
+                    )
+                ORDER BY traded_at
+                LIMIT ?
+                ;"#,
+            tmp = tmp_table,
+            source = source_table,
+            target = target_table
+        );
+        trace!("run stmt: {}", stmt);
+        self.get_conn()
+            .prep_exec(stmt, (limit,))
+            .map(|result| result.affected_rows())
+            .unwrap()
+    }
+
+    fn upsert_aggregated(
+        &self,
+        target_table: &str,
+        tmp_table: &str,
+        data_table: &str,
+        limit: u32,
+    ) -> u64 {
+        self.get_conn()
+            .start_transaction(false, None, None)
+            .and_then(|mut tx| {
+                let stmt = format!(
+                    r#"INSERT INTO {target} (traded_at,amount,price)
+                        SELECT {tmp}.traded_at,Sum({orig}.amount),Avg({orig}.price)
+                        FROM   {tmp}
+                        LEFT JOIN {orig} ON {tmp}.traded_at = {orig}.traded_at
+                        GROUP  BY traded_at
+                        LIMIT  ?
+                        ON DUPLICATE KEY UPDATE
+                            amount = VALUES(amount),
+                            price = VALUES(price)
+                        ;"#,
+                    target = target_table,
+                    tmp = tmp_table,
+                    orig = data_table
+                );
+                trace!("upsert_stmt: {}", stmt);
+                let t = tx
+                    .prep_exec(stmt, (limit,))
+                    .map(|result| result.affected_rows())
+                    .unwrap();
+
+                // drop used traded_at
+                let drop_stmt = format!(r#"DELETE FROM {tmp} WHERE 1=1 LIMIT ?;"#, tmp = tmp_table);
+                tx.prep_exec(drop_stmt, (limit,)).unwrap();
+
+                tx.commit().unwrap();
+
+                Ok(t)
+            })
+            .unwrap()
+    }
+
+    fn count_rows(&self, table_name: &str) -> u64 {
+        let count: i64 = self
+            .get_conn()
+            .first_exec(format!("SELECT COUNT(*) FROM {};", table_name), ())
+            .unwrap()
+            .unwrap();
+        count as u64
+    }
+
+    fn ensure_stats_table(&self) {
+        if !self.check_existence("aggregation_stats") {
+            self.execute(
+                r"CREATE TABLE aggregation_stats (
+                             run_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+                             exchange VARCHAR(255) NOT NULL,
+                             original_rows BIGINT UNSIGNED NOT NULL,
+                             aggregated_rows BIGINT UNSIGNED NOT NULL,
+                             compression_ratio DOUBLE NOT NULL,
+                             batches INT UNSIGNED NOT NULL,
+                             duration_ms BIGINT UNSIGNED NOT NULL
+                         );",
+            );
+        }
+    }
+
+    fn insert_run_stats(&self, stats: &RunStats) {
+        let stmt = r#"INSERT INTO aggregation_stats
+            (exchange, original_rows, aggregated_rows, compression_ratio, batches, duration_ms)
+            VALUES (?, ?, ?, ?, ?, ?);"#;
+        self.get_conn()
+            .prep_exec(
+                stmt,
+                (
+                    &stats.exchange,
+                    stats.original_rows,
+                    stats.aggregated_rows,
+                    stats.compression_ratio(),
+                    stats.batches,
+                    stats.duration_ms,
+                ),
+            )
+            .unwrap();
+    }
+}
+
+// `interval_secs`-wide bucket start for `alias.traded_at`, e.g. "t" when correlating against a
+// row alias rather than the data table itself.
+fn postgres_bucket_expr(alias: &str, interval_secs: u32) -> String {
+    format!(
+        "to_timestamp(floor(extract(epoch from {}.traded_at)/{n})*{n})",
+        alias,
+        n = interval_secs
+    )
+}
+
+/// Postgres-backed storage, using server-side `COPY` for the bulk-copy path.
+#[derive(Debug)]
+pub struct PostgresBackend {
+    pool: R2d2Pool<PostgresConnectionManager<NoTls>>,
+}
+
+impl PostgresBackend {
+    pub fn new(url: &str, config: PoolConfig) -> Self {
+        let manager = PostgresConnectionManager::new(url.parse().expect("invalid postgres url"), NoTls);
+        let pool = R2d2Pool::builder()
+            .min_idle(Some(config.min_connections as u32))
+            .max_size(config.max_connections as u32)
+            .connection_timeout(config.acquire_timeout)
+            .build(manager)
+            .expect("cannot connect to Postgres instance");
+        Self { pool }
+    }
+
+    // Acquires a connection, failing fast with a clear error instead of hanging indefinitely
+    // when the pool is exhausted (the pool itself is already bounded by `connection_timeout`).
+    fn get_conn(&self) -> r2d2::PooledConnection<PostgresConnectionManager<NoTls>> {
+        self.pool
+            .get()
+            .unwrap_or_else(|e| panic!("could not acquire a Postgres connection: {}", e))
+    }
+}
+
+impl Backend for PostgresBackend {
+    fn execute(&self, stmt: &str) -> u64 {
+        self.get_conn().execute(stmt, &[]).unwrap()
+    }
+
+    fn check_existence(&self, table_name: &str) -> bool {
+        let row = self
+            .get_conn()
+            .query_one(
+                "SELECT COUNT(*) FROM information_schema.tables WHERE table_name = $1",
+                &[&table_name],
+            )
+            .unwrap();
+        let count: i64 = row.get(0);
+        count != 0
+    }
+
+    fn max_traded_at(&self, table_name: &str) -> Option<String> {
+        let row = self
+            .get_conn()
+            .query_one(
+                format!("SELECT MAX(traded_at)::text FROM {};", table_name).as_str(),
+                &[],
+            )
+            .unwrap();
+        row.get(0)
+    }
+
+    fn rename_table(&self, orig: &str, to: &str) {
+        self.execute(&format!(r"ALTER TABLE {} RENAME TO {};", orig, to));
+    }
+
+    fn create_timestamp_table(&self, table_name: &str) {
+        self.execute(&format!(
+            r"CREATE TABLE {} (
+                         traded_at TIMESTAMP(3) NOT NULL PRIMARY KEY
+                     );",
+            table_name
+        ));
+    }
+
+    fn create_aggregate_table(&self, table_name: &str) {
+        self.execute(&format!(
+            r"CREATE TABLE {} (
+                         traded_at TIMESTAMP(3) NOT NULL PRIMARY KEY,
+                         amount DOUBLE PRECISION NOT NULL,
+                         price REAL NOT NULL
+                     );",
+            table_name
+        ));
+    }
+
+    fn create_candle_table(&self, table_name: &str) {
+        self.execute(&format!(
+            r"CREATE TABLE {} (
+                         bucket_start TIMESTAMP NOT NULL PRIMARY KEY,
+                         open REAL NOT NULL,
+                         high REAL NOT NULL,
+                         low REAL NOT NULL,
+                         close REAL NOT NULL,
+                         volume DOUBLE PRECISION NOT NULL,
+                         trade_count INTEGER NOT NULL
+                     );",
+            table_name
+        ));
+    }
+
+    // faster version, using server-side COPY instead of MySQL's OUTFILE/LOAD DATA.
+    fn bulk_insert_distinct_traded_at(
+        &self,
+        source_table: &str,
+        dest_table: &str,
+        limit: u32,
+    ) -> u64 {
+        let out_file_name = format!("/tmp/table_{}_{}.txt", dest_table, Utc::now().timestamp_millis());
+        let mut conn = self.get_conn();
+
+        let copy_out_stmt = format!(
+            r#"COPY (
+                SELECT DISTINCT traded_at
+                FROM {source}
+                WHERE traded_at >
+                    COALESCE((SELECT MAX(traded_at) FROM {dest}), '2000-01-01 00:00:00.000')
+                ORDER BY traded_at
+                LIMIT {limit}
+            ) TO '{of}';"#,
+            source = source_table,
+            dest = dest_table,
+            limit = limit,
+            of = out_file_name
+        );
+        trace!("run stmt: {}", copy_out_stmt);
+        conn.execute(&copy_out_stmt, &[]).unwrap();
+
+        let copy_in_stmt = format!(r#"COPY {dest} FROM '{of}';"#, dest = dest_table, of = out_file_name);
+        trace!("run stmt: {}", copy_in_stmt);
+        conn.execute(&copy_in_stmt, &[]).unwrap()
+    }
+
+    fn insert_aggregated(
+        &self,
+        s1_table: &str,
+        s2_table: &str,
+        data_table: &str,
+        limit: u32,
+    ) -> u64 {
+        let mut conn = self.get_conn();
+        let mut tx = conn.transaction().unwrap();
+
+        let stmt = format!(
+            r#"INSERT INTO {s2} (traded_at, amount, price)
+                SELECT {s1}.traded_at, SUM({orig}.amount), AVG({orig}.price)
+                FROM {s1}
+                LEFT JOIN {orig} ON {s1}.traded_at = {orig}.traded_at
+                GROUP BY {s1}.traded_at
+                ORDER BY {s1}.traded_at
+                LIMIT {limit}
+                ;"#,
+            s1 = s1_table,
+            s2 = s2_table,
+            orig = data_table,
+            limit = limit
+        );
+        trace!("agg_stmt: {}", stmt);
+        let t = tx.execute(stmt.as_str(), &[]).unwrap();
+
+        // drop used traded_at (Postgres has no DELETE ... LIMIT, so target a bounded subselect)
+        let drop_stmt = format!(
+            r#"DELETE FROM {s1} WHERE traded_at IN (SELECT traded_at FROM {s1} ORDER BY traded_at LIMIT {limit});"#,
+            s1 = s1_table,
+            limit = limit
+        );
+        tx.execute(drop_stmt.as_str(), &[]).unwrap();
+
+        tx.commit().unwrap();
+        t
+    }
+
+    fn move_aggregated_candles(
+        &self,
+        data_table: &str,
+        candle_table: &str,
+        interval_secs: u32,
+        limit: u32,
+    ) -> u64 {
+        let bucket = postgres_bucket_expr(data_table, interval_secs);
+        let bucket_t = postgres_bucket_expr("t", interval_secs);
+
+        let stmt = format!(
+            r#"INSERT INTO {candles} (bucket_start, open, high, low, close, volume, trade_count)
+                SELECT b.bucket_start, open_t.price, b.high, b.low, close_t.price, b.volume, b.trade_count
+                FROM (
+                    SELECT {bucket} AS bucket_start,
+                           MAX(price) AS high,
+                           MIN(price) AS low,
+                           SUM(amount) AS volume,
+                           COUNT(*) AS trade_count
+                    FROM {orig}
+                    WHERE {bucket} >
+                        COALESCE((SELECT MAX(bucket_start) FROM {candles}), '2000-01-01 00:00:00')
+                    GROUP BY bucket_start
+                    ORDER BY bucket_start
+                    LIMIT {limit}
+                ) b
+                JOIN {orig} open_t ON open_t.id = (
+                    SELECT t.id FROM {orig} t
+                    WHERE {bucket_t} = b.bucket_start
+                    ORDER BY t.traded_at ASC, t.id ASC
+                    LIMIT 1
+                )
+                JOIN {orig} close_t ON close_t.id = (
+                    SELECT t.id FROM {orig} t
+                    WHERE {bucket_t} = b.bucket_start
+                    ORDER BY t.traded_at DESC, t.id DESC
+                    LIMIT 1
+                )
+                ON CONFLICT (bucket_start) DO UPDATE SET
+                    open = EXCLUDED.open,
+                    high = EXCLUDED.high,
+                    low = EXCLUDED.low,
+                    close = EXCLUDED.close,
+                    volume = EXCLUDED.volume,
+                    trade_count = EXCLUDED.trade_count
+                ;"#,
+            candles = candle_table,
+            orig = data_table,
+            bucket = bucket,
+            bucket_t = bucket_t,
+            limit = limit
+        );
+        trace!("candle_stmt: {}", stmt);
+        self.get_conn().execute(stmt.as_str(), &[]).unwrap()
+    }
+
+    // Several trades can share the exact same traded_at, so open/close are picked by ordering on
+    // (traded_at, id) rather than joining back on traded_at alone (which can match more than one
+    // row and fan out the bucket) or on MIN(id)/MAX(id) alone (wrong whenever insertion id isn't
+    // monotonic with traded_at, e.g. a backfill). id only breaks ties within the same timestamp.
+    fn refresh_latest_candle(&self, data_table: &str, candle_table: &str, interval_secs: u32) {
+        let bucket = postgres_bucket_expr(data_table, interval_secs);
+        let bucket_t = postgres_bucket_expr("t", interval_secs);
+
+        let stmt = format!(
+            r#"INSERT INTO {candles} (bucket_start, open, high, low, close, volume, trade_count)
+                SELECT b.bucket_start, open_t.price, b.high, b.low, close_t.price, b.volume, b.trade_count
+                FROM (
+                    SELECT {bucket} AS bucket_start,
+                           MAX(price) AS high,
+                           MIN(price) AS low,
+                           SUM(amount) AS volume,
+                           COUNT(*) AS trade_count
+                    FROM {orig}
+                    WHERE {bucket} = (SELECT MAX(bucket_start) FROM {candles})
+                    GROUP BY bucket_start
+                ) b
+                JOIN {orig} open_t ON open_t.id = (
+                    SELECT t.id FROM {orig} t
+                    WHERE {bucket_t} = b.bucket_start
+                    ORDER BY t.traded_at ASC, t.id ASC
+                    LIMIT 1
+                )
+                JOIN {orig} close_t ON close_t.id = (
+                    SELECT t.id FROM {orig} t
+                    WHERE {bucket_t} = b.bucket_start
+                    ORDER BY t.traded_at DESC, t.id DESC
+                    LIMIT 1
+                )
+                ON CONFLICT (bucket_start) DO UPDATE SET
+                    open = EXCLUDED.open,
+                    high = EXCLUDED.high,
+                    low = EXCLUDED.low,
+                    close = EXCLUDED.close,
+                    volume = EXCLUDED.volume,
+                    trade_count = EXCLUDED.trade_count
+                ;"#,
+            candles = candle_table,
+            orig = data_table,
+            bucket = bucket,
+            bucket_t = bucket_t,
+        );
+        trace!("refresh_candle_stmt: {}", stmt);
+        self.get_conn().execute(stmt.as_str(), &[]).unwrap();
+    }
+
+    fn collect_incremental_traded_at(
+        &self,
+        source_table: &str,
+        tmp_table: &str,
+        target_table: &str,
+        limit: u32,
+    ) -> u64 {
+        let stmt = format!(
+            r#"INSERT INTO {tmp}
+                SELECT DISTINCT traded_at
+                FROM {source}
+                WHERE traded_at >=
+                    COALESCE((SELECT MAX(traded_at) FROM {target}), '2000-01-01 00:00:00.000')
+                  AND traded_at >
+                    COALESCE((SELECT MAX(traded_at) FROM {tmp}), '1970-01-01 00:00:00.000')
+                ORDER BY traded_at
+                LIMIT {limit}
+                ;"#,
+            tmp = tmp_table,
+            source = source_table,
+            target = target_table,
+            limit = limit
+        );
+        trace!("run stmt: {}", stmt);
+        self.get_conn().execute(stmt.as_str(), &[]).unwrap()
+    }
+
+    fn upsert_aggregated(
+        &self,
+        target_table: &str,
+        tmp_table: &str,
+        data_table: &str,
+        limit: u32,
+    ) -> u64 {
+        let mut conn = self.get_conn();
+        let mut tx = conn.transaction().unwrap();
+
+        let stmt = format!(
+            r#"INSERT INTO {target} (traded_at, amount, price)
+                SELECT {tmp}.traded_at, SUM({orig}.amount), AVG({orig}.price)
+                FROM {tmp}
+                LEFT JOIN {orig} ON {tmp}.traded_at = {orig}.traded_at
+                GROUP BY {tmp}.traded_at
+                ORDER BY {tmp}.traded_at
+                LIMIT {limit}
+                ON CONFLICT (traded_at) DO UPDATE SET
+                    amount = EXCLUDED.amount,
+                    price = EXCLUDED.price
+                ;"#,
+            target = target_table,
+            tmp = tmp_table,
+            orig = data_table,
+            limit = limit
+        );
+        trace!("upsert_stmt: {}", stmt);
+        let t = tx.execute(stmt.as_str(), &[]).unwrap();
+
+        // drop used traded_at (Postgres has no DELETE ... LIMIT, so target a bounded subselect)
+        let drop_stmt = format!(
+            r#"DELETE FROM {tmp} WHERE traded_at IN (SELECT traded_at FROM {tmp} ORDER BY traded_at LIMIT {limit});"#,
+            tmp = tmp_table,
+            limit = limit
+        );
+        tx.execute(drop_stmt.as_str(), &[]).unwrap();
+
+        tx.commit().unwrap();
+        t
+    }
+
+    fn count_rows(&self, table_name: &str) -> u64 {
+        let row = self
+            .get_conn()
+            .query_one(format!("SELECT COUNT(*) FROM {};", table_name).as_str(), &[])
+            .unwrap();
+        let count: i64 = row.get(0);
+        count as u64
+    }
+
+    fn ensure_stats_table(&self) {
+        if !self.check_existence("aggregation_stats") {
+            self.execute(
+                r"CREATE TABLE aggregation_stats (
+                             run_at TIMESTAMP NOT NULL DEFAULT now(),
+                             exchange VARCHAR(255) NOT NULL,
+                             original_rows BIGINT NOT NULL,
+                             aggregated_rows BIGINT NOT NULL,
+                             compression_ratio DOUBLE PRECISION NOT NULL,
+                             batches INTEGER NOT NULL,
+                             duration_ms BIGINT NOT NULL
+                         );",
+            );
+        }
+    }
+
+    fn insert_run_stats(&self, stats: &RunStats) {
+        let stmt = r#"INSERT INTO aggregation_stats
+            (exchange, original_rows, aggregated_rows, compression_ratio, batches, duration_ms)
+            VALUES ($1, $2, $3, $4, $5, $6);"#;
+        self.get_conn()
+            .execute(
+                stmt,
+                &[
+                    &stats.exchange,
+                    &(stats.original_rows as i64),
+                    &(stats.aggregated_rows as i64),
+                    &stats.compression_ratio(),
+                    &(stats.batches as i32),
+                    &(stats.duration_ms as i64),
+                ],
+            )
+            .unwrap();
+    }
+}
@@ -0,0 +1,30 @@
+//! Tunable parameters for a run, so connection counts and batch sizes can be bounded without
+//! recompiling.
+
+use std::time::Duration;
+
+/// Connection-pool sizing and batching knobs for a single [`crate::Aggregator`].
+///
+/// With several exchanges aggregated concurrently, an unbounded pool per `Aggregator` can push a
+/// deployment past the DB's `max_connections`. Capping `max_connections` here, and failing fast
+/// via `acquire_timeout` rather than hanging, keeps the total connection count predictable.
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub min_connections: usize,
+    pub max_connections: usize,
+    pub acquire_timeout: Duration,
+    /// Row `LIMIT` used when batching step1/step2/candle work; lower it to reduce per-batch
+    /// memory and lock time on large tables, raise it to reduce round-trips.
+    pub batch_limit: u32,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            min_connections: 1,
+            max_connections: 10,
+            acquire_timeout: Duration::from_secs(10),
+            batch_limit: 100_000,
+        }
+    }
+}
@@ -3,31 +3,61 @@
 //! This tool collaborates with `pikmin`, which is a downloader for execution data, so that
 //! reduce data sizes by aggregating rows at the same timestamp.
 //!
-//! Currently, this only supports MySQL.
+//! Storage access is factored behind the `Backend` trait, so aggregation runs the same way
+//! regardless of which engine a given `Aggregator` is pointed at. See [`backend::MysqlBackend`]
+//! and [`backend::PostgresBackend`].
+//!
+//! Aggregation itself runs as an async pipeline: a producer task fetches distinct `traded_at`
+//! batches while a consumer task aggregates already-fetched batches concurrently, overlapping
+//! the fetch and aggregate stages instead of running them fully in sequence.
+
+mod backend;
+mod config;
+mod metrics;
+
+pub use backend::{Backend, MysqlBackend, PostgresBackend};
+pub use config::PoolConfig;
+pub use metrics::RunStats;
 
-use chrono::Utc;
-use log::trace;
-use mysql::Pool;
+use std::sync::Arc;
+use std::time::Instant;
+
+use log::{info, trace};
+use tokio::sync::mpsc;
+use tokio::task;
 
 /// A struct, which keeps a DB connection, to aggregate data for one exchange.
 #[derive(Debug)]
-pub struct Aggregator<'a> {
-    pool: Pool,
-    exchange_name: &'a str,
+pub struct Aggregator<B: Backend> {
+    backend: Arc<B>,
+    exchange_name: String,
+    batch_limit: u32,
+    /// Whether to count rows and persist a `RunStats` row after each run. Off by default since
+    /// counting rows on a large table is itself expensive.
+    report_stats: bool,
 }
 
-impl<'a> Aggregator<'a> {
-    /// Creates an aggregator instance with a DB url and an exchange name (table name).
-    pub fn new(url: &str, exchange_name: &'a str) -> Self {
-        let pool: Pool = Pool::new(url).expect("cannot connect to MySQL instance");
+impl<B: Backend + 'static> Aggregator<B> {
+    /// Creates an aggregator instance with a storage backend and an exchange name (table name),
+    /// batching step1/step2/candle work by `batch_limit` rows at a time.
+    pub fn new(backend: B, exchange_name: impl Into<String>, batch_limit: u32) -> Self {
         Self {
-            pool,
-            exchange_name,
+            backend: Arc::new(backend),
+            exchange_name: exchange_name.into(),
+            batch_limit,
+            report_stats: false,
         }
     }
 
-    /// Run aggregation
-    pub fn aggregate(&self) {
+    /// Enables counting rows and recording a [`RunStats`] row into `aggregation_stats` after
+    /// each run, and logging it as a structured line.
+    pub fn with_report_stats(mut self, report_stats: bool) -> Self {
+        self.report_stats = report_stats;
+        self
+    }
+
+    /// Run aggregation, overlapping the step1 fetch batches with step2 aggregate batches.
+    pub async fn aggregate(&self) {
         trace!("start aggregate: {}", self.exchange_name);
         trace!(
             "original: {}, target: {}",
@@ -35,42 +65,62 @@ impl<'a> Aggregator<'a> {
             self.target_table()
         );
 
-        if !self.check_existence(&self.target_table()) {
-            let step1_table_name = self.get_step1_table_name(&self.original_table());
-            if !self.check_existence(&step1_table_name) {
-                self.step1(&step1_table_name);
-            }
+        let started_at = Instant::now();
+        let mut batches = 0;
 
+        if !self.backend.check_existence(&self.target_table()) {
             let step2_table_name = self.get_step2_table_name(&self.original_table());
-            if !self.check_existence(&step2_table_name) {
+            if !self.backend.check_existence(&step2_table_name) {
                 self.clear_step2_table(&step2_table_name);
             }
 
-            self.step2(&self.original_table(), &step1_table_name, &step2_table_name);
-            self.rename_table(&step2_table_name, &self.target_table());
-            self.drop_table(&step1_table_name);
-        }
+            batches = self.pipeline(&step2_table_name).await;
 
-        trace!("finish: {}", self.exchange_name);
-    }
+            self.backend
+                .rename_table(&step2_table_name, &self.target_table());
+        }
 
-    fn check_existence(&self, table_name: &str) -> bool {
-        let existence_stmt = format!(r"SHOW TABLES LIKE '{}';", table_name);
-        let exists = self
-            .pool
-            .prep_exec(existence_stmt, ())
-            .map(|result| result.map(|x| x.unwrap()).count());
-        exists.map(|e| e != 0).unwrap_or(false)
-    }
+        if self.report_stats {
+            self.record_stats(batches, started_at.elapsed()).await;
+        }
 
-    fn drop_table(&self, table_name: &str) {
-        let drop_stmt = format!(r"DROP TABLE IF EXISTS {};", table_name);
-        self.pool.prep_exec(drop_stmt, ()).unwrap();
+        trace!("finish: {}", self.exchange_name);
     }
 
-    fn rename_table(&self, orig: &str, to: &str) {
-        let rename_stmt = format!(r"RENAME TABLE {} TO {};", orig, to);
-        self.pool.prep_exec(rename_stmt, ()).unwrap();
+    // Counts rows, persists a RunStats row, and logs it as a structured line.
+    async fn record_stats(&self, batches: u32, duration: std::time::Duration) {
+        let backend = Arc::clone(&self.backend);
+        let exchange = self.exchange_name.clone();
+        let original_table = self.original_table();
+        let target_table = self.target_table();
+        let duration_ms = duration.as_millis() as u64;
+
+        let stats = task::spawn_blocking(move || {
+            backend.ensure_stats_table();
+            let original_rows = backend.count_rows(&original_table);
+            let aggregated_rows = backend.count_rows(&target_table);
+            let stats = RunStats {
+                exchange,
+                original_rows,
+                aggregated_rows,
+                batches,
+                duration_ms,
+            };
+            backend.insert_run_stats(&stats);
+            stats
+        })
+        .await
+        .unwrap();
+
+        info!(
+            "aggregation stats: exchange={} original_rows={} aggregated_rows={} compression_ratio={:.4} batches={} duration_ms={}",
+            stats.exchange,
+            stats.original_rows,
+            stats.aggregated_rows,
+            stats.compression_ratio(),
+            stats.batches,
+            stats.duration_ms
+        );
     }
 
     fn get_temporary_table_name(&self, table_name: &str) -> String {
@@ -92,148 +142,192 @@ impl<'a> Aggregator<'a> {
         format!("step2__{}", table_name)
     }
 
-    // faster version, using OUTFILE/LOAD.
-    // https://github.com/docker-library/mysql/issues/447
-    fn insert_all_traded_at(&self, table_name: &str) -> u64 {
-        const LIMIT: u32 = 100000;
+    fn candle_table(&self) -> String {
+        format!("candles_{}", self.exchange_name)
+    }
 
-        let out_file_name = format!(
-            "/tmp/table_{}_{}.txt",
-            self.exchange_name,
-            Utc::now().timestamp_millis()
-        );
+    fn clear_step2_table(&self, s2_table_name: &str) {
+        self.backend.drop_table(&s2_table_name);
+        self.backend.create_aggregate_table(&s2_table_name);
+    }
 
-        // insert
-        {
-            // cannot use prepared statement
-            let stmt = format!(
-                r#"SELECT DISTINCT traded_at
-                    FROM {}
-                    WHERE traded_at >
-                    COALESCE(
-                        (SELECT traded_at FROM {} ORDER BY traded_at DESC LIMIT 1),
-                        '2000-01-01 00:00:00.000'
-                    )
-                    ORDER BY traded_at
-                    LIMIT {lm}
-                    INTO OUTFILE '{of}'
-                        ;"#,
-                self.original_table(),
-                table_name,
-                of = out_file_name,
-                lm = LIMIT
-            );
-            trace!("run stmt: {}", stmt);
-            self.pool.get_conn().unwrap().query(stmt).unwrap();
-        }
-        // load
-        let result = {
-            // cannot use prepared statement
-            let stmt = format!(
-                r#"LOAD DATA INFILE '{of}' INTO TABLE {};"#,
-                table_name,
-                of = out_file_name
-            );
-            trace!("run stmt: {}", stmt);
-            self.pool
-                .get_conn()
-                .unwrap()
-                .query(stmt)
-                .map(|result| result.affected_rows())
-                .unwrap()
+    // Runs step1 (fetch distinct traded_at in batches) and step2 (aggregate those batches) as
+    // two tasks joined by a bounded channel: the producer sends a notification per batch it
+    // writes, and the consumer drains and aggregates whatever has landed so far. The channel's
+    // capacity bounds how far the fetcher can run ahead of the aggregator.
+    //
+    // The producer only ever inserts into step1_table_name and never deletes from it, so its
+    // bulk_insert_distinct_traded_at watermark (MAX(traded_at) of its own dest table) only ever
+    // grows and can't be corrupted by concurrent draining.
+    //
+    // The consumer copies newly landed rows from step1_table_name into tmp_table_name before
+    // aggregating and draining tmp_table_name. It can't watermark that copy off tmp_table_name's
+    // own MAX(traded_at) the way bulk_insert_distinct_traded_at does: insert_aggregated deletes
+    // every row it aggregates from tmp_table_name, so tmp_table_name's MAX resets each time it's
+    // drained, and the next copy would re-copy (and re-aggregate) timestamps step2 already holds
+    // — a plain INSERT with no ON DUPLICATE KEY/ON CONFLICT, so that panics on the primary key.
+    // Instead the consumer tracks its own cursor across iterations and copies everything past it
+    // via copy_distinct_traded_at_since, independent of tmp_table_name's contents.
+    async fn pipeline(&self, step2_table_name: &str) -> u32 {
+        const CHANNEL_CAPACITY: usize = 4;
+
+        let batch_limit = self.batch_limit;
+        let step1_table_name = self.get_step1_table_name(&self.original_table());
+        let tmp_table_name = self.get_temporary_table_name(&step1_table_name);
+        self.backend.drop_table(&step1_table_name);
+        self.backend.create_timestamp_table(&step1_table_name);
+        self.backend.drop_table(&tmp_table_name);
+        self.backend.create_timestamp_table(&tmp_table_name);
+
+        let (tx, mut rx) = mpsc::channel::<()>(CHANNEL_CAPACITY);
+
+        let producer = {
+            let backend = Arc::clone(&self.backend);
+            let original_table = self.original_table();
+            let step1_table_name = step1_table_name.clone();
+            task::spawn_blocking(move || {
+                let mut batches = 0;
+                loop {
+                    let inserted = backend.bulk_insert_distinct_traded_at(
+                        &original_table,
+                        &step1_table_name,
+                        batch_limit,
+                    );
+                    if inserted == 0 {
+                        break;
+                    }
+                    batches += 1;
+                    if tx.blocking_send(()).is_err() {
+                        break;
+                    }
+                }
+                batches
+            })
         };
 
-        result
-    }
+        let consumer = {
+            let backend = Arc::clone(&self.backend);
+            let data_table_name = self.original_table();
+            let step1_table_name = step1_table_name.clone();
+            let tmp_table_name = tmp_table_name.clone();
+            let step2_table_name = step2_table_name.to_string();
+            task::spawn_blocking(move || {
+                let mut cursor = "2000-01-01 00:00:00.000".to_string();
+                while rx.blocking_recv().is_some() {
+                    loop {
+                        let copied = backend.copy_distinct_traded_at_since(
+                            &step1_table_name,
+                            &tmp_table_name,
+                            &cursor,
+                            batch_limit,
+                        );
+                        if copied == 0 {
+                            break;
+                        }
+                        if let Some(new_cursor) = backend.max_traded_at(&tmp_table_name) {
+                            cursor = new_cursor;
+                        }
+                    }
+
+                    while backend.insert_aggregated(
+                        &tmp_table_name,
+                        &step2_table_name,
+                        &data_table_name,
+                        batch_limit,
+                    ) != 0
+                    {}
+                }
+            })
+        };
 
-    // write all traded_at in the specified term
-    fn step1(&self, table_name: &str) {
-        let tmp_table_name = self.get_temporary_table_name(&table_name);
+        let (producer, consumer) = tokio::join!(producer, consumer);
+        let batches = producer.unwrap();
+        consumer.unwrap();
 
-        self.drop_table(&tmp_table_name);
-        let create_stmt = format!(
-            r"CREATE TABLE {} (
-                                 traded_at TIMESTAMP(3) NOT NULL PRIMARY KEY
-                             );",
-            tmp_table_name
-        );
-        self.pool.prep_exec(create_stmt, ()).unwrap();
-
-        // fetch all traded_at, and insert it
-        let mut i = 0;
-        loop {
-            trace!("[{}] offset: {}", self.exchange_name, i);
-            let inserted = self.insert_all_traded_at(&tmp_table_name);
-            i += 1;
-            if inserted == 0 {
-                break;
+        self.backend.drop_table(&tmp_table_name);
+        self.backend.drop_table(&step1_table_name);
+
+        batches
+    }
+
+    /// Runs incremental aggregation, upserting aggregated rows directly into
+    /// `ref_trades_<exchange>` instead of building a fresh table and renaming it into place.
+    /// Safe to re-run: each run re-aggregates the latest existing `traded_at` bucket (in case
+    /// more trades landed at that exact timestamp since the previous run) before topping up
+    /// anything newer, so a crash or repeat run only re-does a little work rather than leaving
+    /// an orphaned temp table or rebuilding from scratch.
+    pub async fn aggregate_incremental(&self) {
+        trace!("start aggregate_incremental: {}", self.exchange_name);
+
+        let backend = Arc::clone(&self.backend);
+        let batch_limit = self.batch_limit;
+        let original_table = self.original_table();
+        let target_table = self.target_table();
+        let step1_table_name = self.get_step1_table_name(&original_table);
+        let tmp_table_name = self.get_temporary_table_name(&step1_table_name);
+
+        task::spawn_blocking(move || {
+            if !backend.check_existence(&target_table) {
+                backend.create_aggregate_table(&target_table);
             }
-        }
 
-        self.rename_table(&tmp_table_name, &table_name);
+            backend.drop_table(&tmp_table_name);
+            backend.create_timestamp_table(&tmp_table_name);
+
+            while backend.collect_incremental_traded_at(
+                &original_table,
+                &tmp_table_name,
+                &target_table,
+                batch_limit,
+            ) != 0
+            {}
+
+            while backend.upsert_aggregated(&target_table, &tmp_table_name, &original_table, batch_limit)
+                != 0
+            {}
+
+            backend.drop_table(&tmp_table_name);
+        })
+        .await
+        .unwrap();
+
+        trace!("finish aggregate_incremental: {}", self.exchange_name);
     }
 
-    fn clear_step2_table(&self, s2_table_name: &str) {
-        self.drop_table(&s2_table_name);
-
-        let create_stmt = format!(
-            r"CREATE TABLE {} (
-                         traded_at TIMESTAMP(3) NOT NULL PRIMARY KEY,
-                         amount DOUBLE NOT NULL,
-                         price FLOAT NOT NULL
-                     );",
-            s2_table_name
+    /// Runs OHLCV candle aggregation, bucketing trades into `interval_secs`-wide buckets and
+    /// writing them into `candles_<exchange>`. Unlike `aggregate`, this writes straight into the
+    /// target table: it refreshes the latest existing bucket (in case more trades landed in it
+    /// since the previous run) and then only considers buckets newer than it, so it can be
+    /// re-run to top up a table as new trades arrive.
+    pub async fn aggregate_candles(&self, interval_secs: u32) {
+        let batch_limit = self.batch_limit;
+
+        trace!(
+            "start aggregate_candles: {} ({}s buckets)",
+            self.exchange_name,
+            interval_secs
         );
-        self.pool.prep_exec(create_stmt, ()).unwrap();
-    }
 
-    // TODO: in parallel
-    fn move_aggregated_data(
-        &self,
-        s1_table_name: &str,
-        s2_table_name: &str,
-        data_table_name: &str,
-    ) -> u64 {
-        const LIMIT: u32 = 100000;
-
-        trace!("insert_aggregated_data: limit: {}", LIMIT);
-
-        self.pool
-            .start_transaction(false, None, None)
-            .and_then(|mut tx| {
-                let stmt = format!(
-                    r#"INSERT INTO {s2} (traded_at,amount,price)
-                        SELECT {s1}.traded_at,Sum({orig}.amount),Avg({orig}.price)
-                        FROM   {s1}
-                        LEFT JOIN {orig} ON {s1}.traded_at = {orig}.traded_at
-                        GROUP  BY traded_at
-                        LIMIT  ?
-                        ;"#,
-                    s1 = s1_table_name,
-                    s2 = s2_table_name,
-                    orig = data_table_name
-                );
-                trace!("agg_stmt: {}", stmt);
-                let t = tx
-                    .prep_exec(stmt, (LIMIT, ))
-                    .map(|result| result.affected_rows())
-                    .unwrap();
-
-                // drop used traded_at
-                let drop_stmt =
-                    format!(r#"DELETE FROM {s1} WHERE 1=1 LIMIT ?;"#, s1 = s1_table_name);
-                tx.prep_exec(drop_stmt, (LIMIT, )).unwrap();
-
-                tx.commit().unwrap();
-
-                Ok(t)
-            })
-            .unwrap()
-    }
+        if !self.backend.check_existence(&self.candle_table()) {
+            self.backend.create_candle_table(&self.candle_table());
+        }
 
-    // write aggregated execution data
-    fn step2(&self, data_table_name: &str, s1_table_name: &str, s2_table_name: &str) {
-        // fetch all traded_at, and insert it
-        while self.move_aggregated_data(s1_table_name, s2_table_name, data_table_name) != 0 {}
+        let backend = Arc::clone(&self.backend);
+        let original_table = self.original_table();
+        let candle_table = self.candle_table();
+        task::spawn_blocking(move || {
+            backend.refresh_latest_candle(&original_table, &candle_table, interval_secs);
+            while backend.move_aggregated_candles(
+                &original_table,
+                &candle_table,
+                interval_secs,
+                batch_limit,
+            ) != 0
+            {}
+        })
+        .await
+        .unwrap();
+
+        trace!("finish aggregate_candles: {}", self.exchange_name);
     }
 }
@@ -0,0 +1,26 @@
+//! Reporting on how effective and how slow an aggregation run was.
+//!
+//! Counting rows on a large table isn't free, so this is opt-in: the `report_stats` flag set via
+//! `Aggregator::with_report_stats` decides whether a run pays for it.
+
+/// One run's compression and timing numbers for a single exchange.
+#[derive(Debug, Clone)]
+pub struct RunStats {
+    pub exchange: String,
+    pub original_rows: u64,
+    pub aggregated_rows: u64,
+    pub batches: u32,
+    pub duration_ms: u64,
+}
+
+impl RunStats {
+    /// Fraction of rows removed by aggregation, e.g. `0.9` means the aggregated table has 10%
+    /// as many rows as the original. `0.0` when there were no original rows to compress.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.original_rows == 0 {
+            0.0
+        } else {
+            1.0 - (self.aggregated_rows as f64 / self.original_rows as f64)
+        }
+    }
+}